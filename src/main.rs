@@ -1,26 +1,492 @@
-use tokio::io::AsyncWriteExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
 
 #[tokio::main]
 async fn main() {
     let listener = TcpListener::bind("127.0.0.1:8181").await.unwrap();
 
+    let config = Config::from_env();
+
+    let mut router = Router::new();
+    router.register("GET", "/balance", Box::new(BalanceHandler));
+    let router = Arc::new(router);
+
+    // Broadcast a shutdown to every in-flight connection, and keep their join
+    // handles so we can drain them before the process exits.
+    let (shutdown_tx, _) = broadcast::channel::<()>(1);
+    let mut tasks = JoinSet::new();
+
     loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        tokio::spawn(async move {
-            handle_connection(stream).await;
-        });
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((stream, _)) => {
+                        let shutdown_rx = shutdown_tx.subscribe();
+                        match &config.mode {
+                            Mode::Serve => {
+                                let router = Arc::clone(&router);
+                                let max_body_size = config.max_body_size;
+                                tasks.spawn(async move {
+                                    handle_connection(stream, router, shutdown_rx, max_body_size)
+                                        .await;
+                                });
+                            }
+                            Mode::Proxy { backend } => {
+                                let backend = backend.clone();
+                                tasks.spawn(async move {
+                                    proxy_connection(stream, backend, shutdown_rx).await;
+                                });
+                            }
+                        }
+                    }
+                    // A transient accept failure should not abort the listener.
+                    Err(e) => eprintln!("accept error: {}", e),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("shutdown signal received, draining connections");
+                break;
+            }
+        }
+    }
+
+    // Stop accepting, tell live connections to wind down, and wait for them.
+    let _ = shutdown_tx.send(());
+    while tasks.join_next().await.is_some() {}
+}
+
+// Whether the server answers requests locally or forwards them to an upstream.
+enum Mode {
+    Serve,
+    Proxy { backend: String },
+}
+
+// Runtime configuration for the binary, sourced from the environment so the
+// same binary can act as the balance API or as a front door to a backend.
+struct Config {
+    mode: Mode,
+    max_body_size: usize,
+}
+
+// Default cap on a request body before we reject it with `413`.
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Cap on the header block (and any single CRLF-terminated line) so a client
+// that never sends a terminator cannot grow the buffer without bound.
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+impl Config {
+    // `MODE=proxy` switches the server into reverse-proxy mode, forwarding to
+    // `BACKEND_ADDR=host:port` (127.0.0.1:9000 when unset); any other `MODE`
+    // keeps the local serve behavior. `MAX_BODY_SIZE` overrides the
+    // per-request body cap.
+    fn from_env() -> Self {
+        let mode = match std::env::var("MODE").as_deref() {
+            Ok("proxy") => {
+                let backend = std::env::var("BACKEND_ADDR")
+                    .unwrap_or_else(|_| "127.0.0.1:9000".to_string());
+                Mode::Proxy { backend }
+            }
+            _ => Mode::Serve,
+        };
+        let max_body_size = std::env::var("MAX_BODY_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+        Config {
+            mode,
+            max_body_size,
+        }
+    }
+}
+
+// A parsed view of the request line plus the keep-alive decision, handed to a
+// `Handler` so it can produce a `Response`.
+struct Request {
+    method: String,
+    path: String,
+    keep_alive: bool,
+    // Populated by the bounded body reader; kept for handlers (e.g. a future
+    // POST `/transfer`) that consume the payload.
+    #[allow(dead_code)]
+    body: Vec<u8>,
+}
+
+// The pieces of an HTTP response a handler cares about; the wire framing and
+// connection header are filled in when it is serialized.
+struct Response {
+    status: String,
+    content_type: String,
+    body: String,
+}
+
+impl Response {
+    fn new(status: &str, content_type: &str, body: &str) -> Self {
+        Response {
+            status: status.to_string(),
+            content_type: content_type.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    // Render the response on the wire, advertising the connection disposition
+    // so the client knows whether it may reuse the socket.
+    fn serialize(&self, keep_alive: bool) -> String {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            connection,
+            self.body
+        )
+    }
+}
+
+// A unit of endpoint behavior. Each handler owns one `(method, path)` slot in
+// the `Router` and turns a request into a response.
+#[async_trait]
+trait Handler: Send + Sync {
+    async fn handle(&self, req: &Request) -> Response;
+}
+
+// Reproduces the original fixed balance endpoint.
+struct BalanceHandler;
+
+#[async_trait]
+impl Handler for BalanceHandler {
+    async fn handle(&self, _req: &Request) -> Response {
+        Response::new("200 OK", "application/json", "{\"balance\": 0.00}")
+    }
+}
+
+// Maps `(method, path)` keys to the handler that serves them. New endpoints
+// register a handler here rather than editing `handle_connection`.
+struct Router {
+    routes: HashMap<(String, String), Box<dyn Handler>>,
+}
+
+impl Router {
+    fn new() -> Self {
+        Router {
+            routes: HashMap::new(),
+        }
+    }
+
+    fn register(&mut self, method: &str, path: &str, handler: Box<dyn Handler>) {
+        self.routes
+            .insert((method.to_string(), path.to_string()), handler);
+    }
+
+    // Select and run the registered handler for this request, falling back to a
+    // 405 when the path exists under another method and a 404 otherwise.
+    async fn dispatch(&self, req: &Request) -> Response {
+        let key = (req.method.clone(), req.path.clone());
+        if let Some(handler) = self.routes.get(&key) {
+            return handler.handle(req).await;
+        }
+
+        if self.routes.keys().any(|(_, path)| path == &req.path) {
+            Response::new("405 Method Not Allowed", "text/plain", "Method Not Allowed")
+        } else {
+            Response::new("404 Not Found", "text/plain", "Not Found")
+        }
     }
 }
 
-async fn handle_connection(mut stream: TcpStream) {
-    let contents = "{\"balance\": 0.00}";
+async fn handle_connection(
+    mut stream: TcpStream,
+    router: Arc<Router>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+    max_body_size: usize,
+) {
+    // Bytes already read past the current request's header block. With
+    // keep-alive a single `read` can deliver the tail of one request and the
+    // head of the next, so we carry the surplus into the following iteration.
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        // Drain the stream until we have seen the end of the header block,
+        // bailing out early if a shutdown is signalled while we wait.
+        let header_end = loop {
+            if let Some(pos) = find_header_end(&buffer) {
+                break pos;
+            }
+            // Refuse a header block that never terminates before the cap.
+            if buffer.len() > MAX_HEADER_SIZE {
+                let response = Response::new(
+                    "431 Request Header Fields Too Large",
+                    "text/plain",
+                    "Request Header Fields Too Large",
+                );
+                let _ = stream.write_all(response.serialize(false).as_bytes()).await;
+                let _ = stream.flush().await;
+                return;
+            }
+            tokio::select! {
+                read = stream.read(&mut chunk) => match read {
+                    Ok(0) => return,
+                    Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                    Err(_) => return,
+                },
+                _ = shutdown_rx.recv() => return,
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+        let request_line = headers.lines().next().unwrap_or_default();
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let version = parts.next().unwrap_or_default();
+        let keep_alive = wants_keep_alive(version, &headers);
+
+        // Drop the header block so the buffer now starts at the body, then read
+        // the body within the configured limit.
+        buffer.drain(..header_end);
+        let body = match read_body(&mut stream, &mut buffer, &headers, &method, max_body_size).await
+        {
+            Ok(body) => body,
+            Err(status) => {
+                let response = Response::new(status, "text/plain", status);
+                let _ = stream.write_all(response.serialize(false).as_bytes()).await;
+                let _ = stream.flush().await;
+                break;
+            }
+        };
+
+        let request = Request {
+            method,
+            path,
+            keep_alive,
+            body,
+        };
+
+        let response = router.dispatch(&request).await;
+
+        if stream
+            .write_all(response.serialize(request.keep_alive).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        if stream.flush().await.is_err() {
+            return;
+        }
+
+        // Stop reusing the socket once a shutdown has been requested.
+        let shutting_down = shutdown_rx.try_recv().is_ok();
+        if !request.keep_alive || shutting_down {
+            break;
+        }
+    }
+
+    // Half-close the write side after the final response so the peer sees a
+    // clean end-of-response.
+    let _ = stream.shutdown().await;
+}
+
+// Splice a client connection onto a freshly opened backend connection, copying
+// bytes in both directions until either side (or a shutdown signal) finishes.
+async fn proxy_connection(
+    client: TcpStream,
+    backend: String,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let server = match TcpStream::connect(&backend).await {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("backend connect error ({}): {}", backend, e);
+            return;
+        }
+    };
+
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut server_read, mut server_write) = server.into_split();
+
+    let client_to_server = tokio::io::copy(&mut client_read, &mut server_write);
+    let server_to_client = tokio::io::copy(&mut server_read, &mut client_write);
+
+    // Whichever direction finishes (or errors) first tears down the pair.
+    tokio::select! {
+        _ = client_to_server => {}
+        _ = server_to_client => {}
+        _ = shutdown_rx.recv() => {}
+    }
+}
+
+// Decide whether the connection should stay open after this response. HTTP/1.1
+// defaults to keep-alive unless the client sends `Connection: close`; HTTP/1.0
+// (and anything older) defaults to closing unless it opts in with
+// `Connection: keep-alive`.
+fn wants_keep_alive(version: &str, headers: &str) -> bool {
+    let connection = header_value(headers, "connection").map(|value| value.to_ascii_lowercase());
+
+    // `Connection` is a comma-separated token list, so inspect each token
+    // rather than matching the whole value (e.g. "keep-alive, Upgrade").
+    if let Some(value) = connection.as_deref() {
+        let mut tokens = value.split(',').map(|token| token.trim());
+        if tokens.clone().any(|token| token == "close") {
+            return false;
+        }
+        if tokens.any(|token| token == "keep-alive") {
+            return true;
+        }
+    }
+    version == "HTTP/1.1"
+}
+
+// Scan the accumulated buffer for the "\r\n\r\n" header terminator and return
+// the index just past it, or `None` if it has not arrived yet.
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+// Read the request body (if any) into a bounded buffer. `buffer` starts at the
+// first body byte and is left holding any bytes that belong to the next
+// pipelined request. On failure the returned `&str` is the HTTP status line to
+// send back before closing the connection.
+async fn read_body(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    headers: &str,
+    method: &str,
+    max: usize,
+) -> Result<Vec<u8>, &'static str> {
+    if is_chunked(headers) {
+        return read_chunked_body(stream, buffer, max).await;
+    }
+
+    match content_length(headers) {
+        Some(Ok(len)) => {
+            if len > max {
+                return Err("413 Payload Too Large");
+            }
+            read_sized_body(stream, buffer, len).await
+        }
+        Some(Err(())) => Err("400 Bad Request"),
+        None if method_requires_body(method) => Err("411 Length Required"),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Read exactly `len` bytes, accumulating until the buffer holds the whole body
+// and treating a premature `Ok(0)` as a malformed request.
+async fn read_sized_body(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<Vec<u8>, &'static str> {
+    while buffer.len() < len {
+        read_more(stream, buffer).await?;
+    }
+    let body = buffer[..len].to_vec();
+    buffer.drain(..len);
+    Ok(body)
+}
+
+// Decode a `Transfer-Encoding: chunked` body: each chunk is a hex size line
+// followed by that many bytes and a CRLF, ending with a zero-size chunk.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    max: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let mut body = Vec::new();
+    loop {
+        let size_line = read_line(stream, buffer).await?;
+        let size = usize::from_str_radix(size_line.trim(), 16).map_err(|_| "400 Bad Request")?;
+        if size == 0 {
+            // Consume the trailing CRLF that closes the body.
+            let _ = read_line(stream, buffer).await?;
+            break;
+        }
+        if body.len() + size > max {
+            return Err("413 Payload Too Large");
+        }
+        while buffer.len() < size + 2 {
+            read_more(stream, buffer).await?;
+        }
+        // The chunk data must be followed by a CRLF; anything else is malformed
+        // framing that would mis-parse the next chunk-size line.
+        if &buffer[size..size + 2] != b"\r\n" {
+            return Err("400 Bad Request");
+        }
+        body.extend_from_slice(&buffer[..size]);
+        buffer.drain(..size + 2);
+    }
+    Ok(body)
+}
+
+// Pull one CRLF-terminated line out of the buffer, reading more as needed.
+async fn read_line(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> Result<String, &'static str> {
+    loop {
+        if let Some(pos) = buffer.windows(2).position(|w| w == b"\r\n") {
+            let line = String::from_utf8_lossy(&buffer[..pos]).into_owned();
+            buffer.drain(..pos + 2);
+            return Ok(line);
+        }
+        // Refuse an unterminated line (e.g. an overlong chunk-size line) before
+        // the buffer grows without bound.
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err("400 Bad Request");
+        }
+        read_more(stream, buffer).await?;
+    }
+}
+
+// Append one read's worth of bytes to the buffer, mapping a closed connection
+// or I/O error onto a `400`.
+async fn read_more(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Result<(), &'static str> {
+    let mut chunk = [0u8; 1024];
+    match stream.read(&mut chunk).await {
+        Ok(0) => Err("400 Bad Request"),
+        Ok(n) => {
+            buffer.extend_from_slice(&chunk[..n]);
+            Ok(())
+        }
+        Err(_) => Err("400 Bad Request"),
+    }
+}
+
+// Find the header value for `name`, skipping the request line.
+fn header_value<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+// Parse `Content-Length`, distinguishing "absent" (`None`) from "present but
+// malformed" (`Some(Err)`).
+fn content_length(headers: &str) -> Option<Result<usize, ()>> {
+    header_value(headers, "content-length").map(|value| value.parse().map_err(|_| ()))
+}
+
+fn is_chunked(headers: &str) -> bool {
+    header_value(headers, "transfer-encoding")
+        .map(|value| value.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or(false)
+}
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-        contents.len(),
-        contents
-    );
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+// Methods whose semantics require a declared body length.
+fn method_requires_body(method: &str) -> bool {
+    matches!(method, "POST" | "PUT" | "PATCH")
 }